@@ -1,17 +1,29 @@
 //! マクロ以外の関数や型など。
-use std::sync::OnceLock;
+use std::ffi::OsString;
+use std::os::windows::ffi::OsStringExt;
+use std::path::PathBuf;
+use std::sync::{OnceLock, RwLock};
 
-static DLL_PATH: OnceLock<String> = OnceLock::new();
-static LOADU_RESULT: OnceLock<BOOL> = OnceLock::new();
+static DLL_PATH: RwLock<Option<String>> = RwLock::new(None);
+static LOADU_RESULT: RwLock<Option<BOOL>> = RwLock::new(None);
+
+#[cfg(feature = "dll_main")]
+static H_INSTANCE: OnceLock<usize> = OnceLock::new();
 
 use encoding::{label::encoding_from_windows_code_page, DecoderTrap};
+use winapi::shared::winerror::ERROR_INSUFFICIENT_BUFFER;
 use winapi::um::{
+    errhandlingapi::GetLastError,
+    libloaderapi::{
+        GetModuleFileNameW, GetModuleHandleExW, GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS,
+        GET_MODULE_HANDLE_EX_FLAG_UNCHANGED_REFCOUNT,
+    },
     winbase::{GlobalAlloc, GlobalFree, GMEM_FIXED},
     winnls::GetOEMCP,
 };
 
 pub use std::ffi::c_long;
-pub use winapi::shared::minwindef::{BOOL, FALSE, HGLOBAL, TRUE};
+pub use winapi::shared::minwindef::{BOOL, FALSE, HGLOBAL, HMODULE, TRUE};
 
 #[cfg(feature = "dll_main")]
 pub use winapi::{
@@ -25,16 +37,28 @@ pub use winapi::{
 ///
 /// [`define_load`]: crate::define_load
 pub fn read_dll_path_string() -> Option<String> {
-    DLL_PATH.get().cloned()
+    DLL_PATH.read().unwrap().clone()
 }
 
 /// DLLへのパスを記録する関数。
 ///
+/// 既に記録されている場合は上書きします。
+///
 /// [`define_load`]で定義される`load`か`loadu`時に、この関数が呼ばれます。
 ///
 /// [`define_load`]: crate::define_load
-pub fn register_dll_path(path: String) -> Result<(), String> {
-    DLL_PATH.set(path)
+pub fn register_dll_path(path: String) {
+    *DLL_PATH.write().unwrap() = Some(path);
+}
+
+/// 記録してあるDLLへのパスを消去する関数。
+///
+/// SSPがDLLをアンロードしたあと、同じDLLが再度ロードされた際に前回の記録が残らないよう、
+/// [`define_unload`]で定義される`unload`時に、この関数が呼ばれます。
+///
+/// [`define_unload`]: crate::define_unload
+pub fn clear_dll_path() {
+    *DLL_PATH.write().unwrap() = None;
 }
 
 /// `loadu`の結果を返す関数。
@@ -43,16 +67,93 @@ pub fn register_dll_path(path: String) -> Result<(), String> {
 ///
 /// [`define_load`]: crate::define_load
 pub fn read_loadu_result() -> Option<BOOL> {
-    LOADU_RESULT.get().cloned()
+    *LOADU_RESULT.read().unwrap()
 }
 
 /// `loadu`の結果を記録する関数。
 ///
+/// 既に記録されている場合は上書きします。
+///
 /// [`define_load`]で定義される`loadu`時に、この関数が呼ばれます。
 ///
 /// [`define_load`]: crate::define_load
-pub fn register_loadu_result(result: BOOL) -> Result<(), BOOL> {
-    LOADU_RESULT.set(result)
+pub fn register_loadu_result(result: BOOL) {
+    *LOADU_RESULT.write().unwrap() = Some(result);
+}
+
+/// 記録してある`loadu`の結果を消去する関数。
+///
+/// SSPがDLLをアンロードしたあと、同じDLLが再度ロードされた際に前回の記録が残らないよう、
+/// [`define_unload`]で定義される`unload`時に、この関数が呼ばれます。
+///
+/// [`define_unload`]: crate::define_unload
+pub fn clear_loadu_result() {
+    *LOADU_RESULT.write().unwrap() = None;
+}
+
+/// `DllMain`が記録した`HINSTANCE`を返す関数。
+///
+/// [`define_dll_main`]で`DLL_PROCESS_ATTACH`時に記録されます。
+///
+/// [`define_dll_main`]: crate::define_dll_main
+#[cfg(feature = "dll_main")]
+#[cfg_attr(docsrs, doc(cfg(feature = "dll_main")))]
+pub fn read_h_instance() -> Option<usize> {
+    H_INSTANCE.get().copied()
+}
+
+/// `DllMain`が受けとった`HINSTANCE`を記録する関数。
+///
+/// [`define_dll_main`]で定義される`DllMain`の`DLL_PROCESS_ATTACH`時に、この関数が呼ばれます。
+///
+/// [`define_dll_main`]: crate::define_dll_main
+#[cfg(feature = "dll_main")]
+#[cfg_attr(docsrs, doc(cfg(feature = "dll_main")))]
+pub fn register_h_instance(h_instance: usize) -> Result<(), usize> {
+    H_INSTANCE.set(h_instance)
+}
+
+/// DLL自身のモジュールハンドルから、実際のパスを解決する関数。
+///
+/// ベースウェアが`load`/`loadu`に渡してくる文字列と違い、OEMコードページの範囲外の文字や
+/// 相対パスに影響されず、OSから直接絶対パスを取得します。
+///
+/// `GetModuleHandleExW`に、このクレート内の関数のアドレスを渡して`HMODULE`を取得し、
+/// `GetModuleFileNameW`でパスを取得しています。
+pub fn read_dll_path() -> Option<PathBuf> {
+    let mut h_module: HMODULE = std::ptr::null_mut();
+
+    let succeeded = unsafe {
+        GetModuleHandleExW(
+            GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS | GET_MODULE_HANDLE_EX_FLAG_UNCHANGED_REFCOUNT,
+            read_dll_path as *const () as *const u16,
+            &mut h_module,
+        )
+    };
+
+    if succeeded == 0 {
+        return None;
+    }
+
+    let mut buffer: Vec<u16> = vec![0; 260];
+
+    loop {
+        let len = unsafe { GetModuleFileNameW(h_module, buffer.as_mut_ptr(), buffer.len() as u32) };
+
+        if len == 0 {
+            return None;
+        }
+
+        if len as usize == buffer.len() && unsafe { GetLastError() } == ERROR_INSUFFICIENT_BUFFER {
+            buffer.resize(buffer.len() * 2, 0);
+            continue;
+        }
+
+        buffer.truncate(len as usize);
+        break;
+    }
+
+    Some(PathBuf::from(OsString::from_wide(&buffer)))
 }
 
 /// `u8`のスライスを、OEM codepageで`String`にデコードする関数。
@@ -119,6 +220,17 @@ pub unsafe fn global_free(h: HGLOBAL) {
     unsafe { GlobalFree(h) };
 }
 
+/// `catch_unwind`が捕らえたパニックのペイロードを、文字列に変換する関数。
+pub fn panic_payload_to_string(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -162,4 +274,62 @@ mod tests {
             assert_eq!(result, case.to_vec());
         }
     }
+
+    mod panic_payload_to_string {
+        use super::*;
+
+        #[test]
+        fn checking_value_with_str() {
+            let payload: Box<dyn std::any::Any + Send> = Box::new("oops");
+            assert_eq!(panic_payload_to_string(&*payload), "oops");
+        }
+
+        #[test]
+        fn checking_value_with_string() {
+            let payload: Box<dyn std::any::Any + Send> = Box::new("oops".to_string());
+            assert_eq!(panic_payload_to_string(&*payload), "oops");
+        }
+
+        #[test]
+        fn checking_value_with_unknown() {
+            let payload: Box<dyn std::any::Any + Send> = Box::new(42);
+            assert_eq!(panic_payload_to_string(&*payload), "unknown panic payload");
+        }
+    }
+
+    // register_dll_path/clear_dll_pathは同じグローバルな静的変数を共有しているため、
+    // 上書きと消去の一連の流れを一つのテストの中で確認する。
+    mod dll_path {
+        use super::*;
+
+        #[test]
+        fn register_overwrites_and_clear_resets() {
+            register_dll_path("first".to_string());
+            assert_eq!(read_dll_path_string(), Some("first".to_string()));
+
+            register_dll_path("second".to_string());
+            assert_eq!(read_dll_path_string(), Some("second".to_string()));
+
+            clear_dll_path();
+            assert_eq!(read_dll_path_string(), None);
+        }
+    }
+
+    // register_loadu_result/clear_loadu_resultは同じグローバルな静的変数を共有しているため、
+    // 上書きと消去の一連の流れを一つのテストの中で確認する。
+    mod loadu_result {
+        use super::*;
+
+        #[test]
+        fn register_overwrites_and_clear_resets() {
+            register_loadu_result(TRUE);
+            assert_eq!(read_loadu_result(), Some(TRUE));
+
+            register_loadu_result(FALSE);
+            assert_eq!(read_loadu_result(), Some(FALSE));
+
+            clear_loadu_result();
+            assert_eq!(read_loadu_result(), None);
+        }
+    }
 }
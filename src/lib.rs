@@ -47,8 +47,13 @@
 #![cfg_attr(docsrs, feature(doc_cfg))]
 
 pub mod dll_util;
+pub mod saori;
 
-pub use dll_util::read_dll_path_string;
+#[cfg(feature = "runtime")]
+#[cfg_attr(docsrs, doc(cfg(feature = "runtime")))]
+pub mod runtime;
+
+pub use dll_util::{read_dll_path, read_dll_path_string};
 
 /// 関数`DLLMain`を定義するマクロ。
 ///
@@ -56,7 +61,11 @@ pub use dll_util::read_dll_path_string;
 /// それぞれ省略可で、もし、途中を飛ばしたい場合、`()`を指定してください。それでその時点での処理はなくなります。
 /// 引数なしなら、何もしません。
 ///
+/// `DLL_PROCESS_ATTACH`時に、受けとった`HINSTANCE`を記録します。(記録した値は[`read_h_instance`]で呼び出せます)
+///
 /// featureの`dll_main`が有効になっていないと使用できませんが、基本的な動作には必要ありません。
+///
+/// [`read_h_instance`]: crate::dll_util::read_h_instance
 #[cfg(feature = "dll_main")]
 #[cfg_attr(docsrs, doc(cfg(feature = "dll_main")))]
 #[macro_export]
@@ -79,12 +88,13 @@ macro_rules! define_dll_main {
     ($process_attach:expr, $process_detach:expr, $thread_attach:expr, $thread_detach:expr) => {
         #[no_mangle]
         pub unsafe extern "system" fn DllMain(
-            _h_module: dll_util::HINSTANCE,
+            h_module: dll_util::HINSTANCE,
             ul_reason_for_call: dll_util::DWORD,
             _l_reserved: dll_util::LPVOID,
         ) -> dll_util::BOOL {
             match ul_reason_for_call {
                 dll_util::DLL_PROCESS_ATTACH => {
+                    let _ = dll_util::register_h_instance(h_module as usize);
                     $process_attach;
                 }
                 dll_util::DLL_PROCESS_DETACH => {
@@ -110,10 +120,17 @@ macro_rules! define_dll_main {
 ///
 /// v1.1.0より、関数名は省略不可になりました。
 ///
+/// `load`では、ベースウェアから渡されたパスをOEMコードページでデコードしますが、
+/// デコードに失敗した場合は[`read_dll_path`]でOSから直接解決したパスにフォールバックします。
+///
+/// 渡した関数がパニックした場合、FFI境界を越えて未定義動作になるのを防ぐため、
+/// パニックはこのマクロの内部で捕らえられ、ペイロードを`eprintln!`で出力したうえで`FALSE`を返します。
+///
 /// # Safety
 /// このマクロで定義される関数は、指定された`HGLOBAL`ポインタを [`global_free`] で解放しています。
 ///
 /// [`read_dll_path_string`]: crate::read_dll_path_string
+/// [`read_dll_path`]: crate::dll_util::read_dll_path
 /// [`global_free`]: crate::dll_util::global_free
 #[macro_export]
 macro_rules! define_load {
@@ -134,23 +151,27 @@ macro_rules! define_load {
                 }
             };
 
-            if let Err(e) = dll_util::register_dll_path(path.clone()) {
-                eprintln!("failed to initialize dll path: {}", e);
-                return dll_util::FALSE;
-            }
+            dll_util::register_dll_path(path.clone());
+
+            let load_result =
+                std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| $load_process(&path)))
+                    .unwrap_or_else(|e| {
+                        eprintln!(
+                            "panic in load process: {}",
+                            dll_util::panic_payload_to_string(&*e)
+                        );
+                        false
+                    });
 
-            let result = if $load_process(&path) {
+            let result = if load_result {
                 dll_util::TRUE
             } else {
                 dll_util::FALSE
             };
 
-            if let Err(_e) = dll_util::register_loadu_result(result) {
-                eprintln!("failed to record the result of loadu");
-                dll_util::FALSE
-            } else {
-                result
-            }
+            dll_util::register_loadu_result(result);
+
+            result
         }
 
         #[no_mangle]
@@ -168,15 +189,25 @@ macro_rules! define_load {
 
             let path = match dll_util::decode_from_oem_codepage(&path_raw) {
                 Ok(v) => v,
-                Err(e) => return e,
+                Err(_e) => match dll_util::read_dll_path() {
+                    Some(v) => v.to_string_lossy().into_owned(),
+                    None => return dll_util::FALSE,
+                },
             };
 
-            if let Err(e) = dll_util::register_dll_path(path.clone()) {
-                eprintln!("failed to initialize dll path: {}", e);
-                return dll_util::FALSE;
-            }
+            dll_util::register_dll_path(path.clone());
+
+            let load_result =
+                std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| $load_process(&path)))
+                    .unwrap_or_else(|e| {
+                        eprintln!(
+                            "panic in load process: {}",
+                            dll_util::panic_payload_to_string(&*e)
+                        );
+                        false
+                    });
 
-            if $load_process(&path) {
+            if load_result {
                 dll_util::TRUE
             } else {
                 dll_util::FALSE
@@ -189,9 +220,17 @@ macro_rules! define_load {
 ///
 /// 引数で、requestの内容である`&Vec<u8>`を受けとり、返答である`Vec<i8>`を返す関数名を渡してください。
 ///
+/// featureの`runtime`が有効なら、ここで初めて[`runtime::ensure_started`]を呼び、
+/// 登録されているバックグラウンドランタイムを起動します。
+///
+/// 渡した関数がパニックした場合、FFI境界を越えて未定義動作になるのを防ぐため、
+/// パニックはこのマクロの内部で捕らえられ、ペイロードを`eprintln!`で出力したうえで
+/// `SAORI/1.0 500 Internal Server Error`を返します。
+///
 /// # Safety
 /// このマクロで定義される関数は、指定された`HGLOBAL`ポインタを [`global_free`] で解放しています。
 ///
+/// [`runtime::ensure_started`]: crate::runtime::ensure_started
 /// [`global_free`]: crate::dll_util::global_free
 #[macro_export]
 macro_rules! define_request {
@@ -205,20 +244,111 @@ macro_rules! define_request {
             let s = unsafe { dll_util::hglobal_to_vec_u8(h, *len) };
             unsafe { dll_util::global_free(h) };
 
-            let response_bytes: Vec<i8> = $request_process(&s);
+            #[cfg(feature = "runtime")]
+            runtime::ensure_started();
+
+            let response_bytes: Vec<i8> =
+                std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| $request_process(&s)))
+                    .unwrap_or_else(|e| {
+                        eprintln!(
+                            "panic in request process: {}",
+                            dll_util::panic_payload_to_string(&*e)
+                        );
+                        b"SAORI/1.0 500 Internal Server Error\r\n\r\n\0"
+                            .iter()
+                            .map(|v| *v as i8)
+                            .collect()
+                    });
             dll_util::slice_i8_to_hglobal(len, &response_bytes)
         }
     };
 }
 
+/// 関数`request`を、パース済みの[`SaoriRequest`]を受けとる形で定義するマクロ。
+///
+/// 引数で、[`SaoriRequest`]を受けとり[`SaoriResponse`]を返す関数名を渡してください。
+/// リクエストのパースとレスポンスのバイト列への組み立てはこのマクロが内部で行うため、
+/// [`define_request`]と違って生のバイト列を直接扱う必要はありません。
+///
+/// リクエストのパースに失敗した場合は`SAORI/1.0 400 Bad Request`を返します。
+///
+/// featureの`runtime`が有効なら、ここで初めて[`runtime::ensure_started`]を呼び、
+/// 登録されているバックグラウンドランタイムを起動します。
+///
+/// 渡した関数がパニックした場合、FFI境界を越えて未定義動作になるのを防ぐため、
+/// パニックはこのマクロの内部で捕らえられ、ペイロードを`eprintln!`で出力したうえで
+/// `SAORI/1.0 500 Internal Server Error`を返します。
+///
+/// # Safety
+/// このマクロで定義される関数は、指定された`HGLOBAL`ポインタを [`global_free`] で解放しています。
+///
+/// [`SaoriRequest`]: crate::saori::SaoriRequest
+/// [`SaoriResponse`]: crate::saori::SaoriResponse
+/// [`runtime::ensure_started`]: crate::runtime::ensure_started
+/// [`global_free`]: crate::dll_util::global_free
+#[macro_export]
+macro_rules! define_request_saori {
+    ($request_process:ident) => {
+        #[no_mangle]
+        pub unsafe extern "cdecl" fn request(
+            h: dll_util::HGLOBAL,
+            len: *mut dll_util::c_long,
+        ) -> dll_util::HGLOBAL {
+            // リクエストの取得
+            let s = unsafe { dll_util::hglobal_to_vec_u8(h, *len) };
+            unsafe { dll_util::global_free(h) };
+
+            #[cfg(feature = "runtime")]
+            runtime::ensure_started();
+
+            let response = match saori::SaoriRequest::parse(&s) {
+                Ok(saori_request) => std::panic::catch_unwind(std::panic::AssertUnwindSafe(
+                    || $request_process(saori_request),
+                ))
+                .unwrap_or_else(|e| {
+                    eprintln!(
+                        "panic in request process: {}",
+                        dll_util::panic_payload_to_string(&*e)
+                    );
+                    saori::SaoriResponse::new(500)
+                }),
+                Err(e) => {
+                    eprintln!("failed to parse saori request: {}", e);
+                    saori::SaoriResponse::new(400)
+                }
+            };
+
+            dll_util::slice_i8_to_hglobal(len, &response.build())
+        }
+    };
+}
+
 /// 関数`unload`を定義するマクロ。
 ///
 /// 引数で`bool`を返す関数名を渡してください(省略可)。
+///
+/// ユーザーのコールバックを実行したあと、`load`/`loadu`が記録したDLLへのパスと
+/// `loadu`の結果を消去します。同じDLLが同一プロセス内でアンロードされたあとに再びロードされても、
+/// 前回の記録が残らないようにするためです。
+///
+/// featureの`runtime`が有効なら、[`runtime::stop`]を呼び、バックグラウンドランタイムを停止して
+/// スレッドの終了を待ってから、DLLより長生きするスレッドが残らないようにします。
+///
+/// 渡した関数がパニックした場合、FFI境界を越えて未定義動作になるのを防ぐため、
+/// パニックはこのマクロの内部で捕らえられ、ペイロードを`eprintln!`で出力したうえで`FALSE`を返します。
+///
+/// [`runtime::stop`]: crate::runtime::stop
 #[macro_export]
 macro_rules! define_unload {
     () => {
         #[no_mangle]
         pub extern "cdecl" fn unload() -> dll_util::BOOL {
+            #[cfg(feature = "runtime")]
+            runtime::stop();
+
+            dll_util::clear_dll_path();
+            dll_util::clear_loadu_result();
+
             dll_util::TRUE
         }
     };
@@ -226,7 +356,22 @@ macro_rules! define_unload {
     ($unload_process:ident) => {
         #[no_mangle]
         pub extern "cdecl" fn unload() -> dll_util::BOOL {
-            if $unload_process() {
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe($unload_process))
+                .unwrap_or_else(|e| {
+                    eprintln!(
+                        "panic in unload process: {}",
+                        dll_util::panic_payload_to_string(&*e)
+                    );
+                    false
+                });
+
+            #[cfg(feature = "runtime")]
+            runtime::stop();
+
+            dll_util::clear_dll_path();
+            dll_util::clear_loadu_result();
+
+            if result {
                 dll_util::TRUE
             } else {
                 dll_util::FALSE
@@ -0,0 +1,235 @@
+//! `request`が初めて呼ばれたタイミングで起動する、バックグラウンドランタイムのサブシステム。
+//!
+//! `DllMain`の`DLL_PROCESS_ATTACH`はWindowsのローダーロックの中で実行されるため、
+//! そこでスレッドを立ち上げるのは危険です。このモジュールは、ローダーロックの外側である
+//! `request`の初回呼び出し時までランタイムの起動を遅延させることで、その危険を避けます。
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, RwLock,
+};
+use std::thread::{self, JoinHandle, Thread};
+
+use crate::dll_util::panic_payload_to_string;
+
+/// バックグラウンドで動き続けるランタイムが実装するトレイト。
+///
+/// [`register_runtime_factory`]で登録したファクトリが生成したインスタンスに対して、
+/// 専用のバックグラウンドスレッド上で、起動時に一度[`Runtime::on_start`]が、
+/// 停止時に一度[`Runtime::on_stop`]が呼ばれます。
+///
+/// `request`のコールバックから[`runtime_handle`]経由でインスタンスへ処理を委譲できるよう、
+/// `as_any_mut`で具体的な型までダウンキャストできるようにしてください。
+pub trait Runtime: std::any::Any + Send + Sync {
+    /// ランタイムの起動処理。
+    fn on_start(&mut self);
+
+    /// ランタイムの停止処理。
+    fn on_stop(&mut self);
+
+    /// `request`のコールバックから具体的な型までダウンキャストするための関数。
+    ///
+    /// 実装は通常`self`を返すだけで構いません。
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any;
+}
+
+type RuntimeFactory = Box<dyn Fn() -> Box<dyn Runtime> + Send + Sync>;
+
+/// バックグラウンドスレッドと、その停止を要求するための合図をまとめたもの。
+///
+/// 停止の合図をワーカーごとに持たせているのは、[`stop`]が古いワーカーの後始末を
+/// 終える前に、別のワーカーの起動に影響しないようにするため。
+struct Worker {
+    join_handle: JoinHandle<()>,
+    thread: Thread,
+    stop_requested: Arc<AtomicBool>,
+}
+
+static RUNTIME_FACTORY: RwLock<Option<RuntimeFactory>> = RwLock::new(None);
+static RUNTIME: RwLock<Option<Box<dyn Runtime>>> = RwLock::new(None);
+static WORKER: RwLock<Option<Worker>> = RwLock::new(None);
+
+/// バックグラウンドランタイムを生成するファクトリを登録する関数。
+///
+/// ここで登録するだけではランタイムは起動しません。[`ensure_started`]が呼ばれたときに、
+/// 初めて呼び出し元のスレッドでファクトリが実行され、専用のバックグラウンドスレッドが起動します。
+pub fn register_runtime_factory<F>(factory: F)
+where
+    F: Fn() -> Box<dyn Runtime> + Send + Sync + 'static,
+{
+    *RUNTIME_FACTORY.write().unwrap() = Some(Box::new(factory));
+}
+
+/// まだ起動していなければ、登録されているファクトリからランタイムを起動する関数。
+///
+/// [`define_request`]や[`define_request_saori`]で定義される`request`から、
+/// リクエストの処理より先に呼ばれます。ファクトリが登録されていなければ何もしません。
+///
+/// [`WORKER`]の書き込みロックを関数全体で保持し続けるため、[`stop`]がまだ前のワーカーの
+/// 後始末を終えていない間は、このロックの獲得待ちでブロックされます。これにより、古い
+/// ワーカーの停止が完了する前に新しいワーカーが起動し、`on_stop`を呼ばれずに
+/// ランタイムが差し替えられてしまう競合を防いでいます。
+///
+/// [`define_request`]: crate::define_request
+/// [`define_request_saori`]: crate::define_request_saori
+pub fn ensure_started() {
+    let mut worker = WORKER.write().unwrap();
+    if worker.is_some() {
+        return;
+    }
+
+    let runtime = match RUNTIME_FACTORY.read().unwrap().as_ref() {
+        Some(factory) => factory(),
+        None => return,
+    };
+
+    *RUNTIME.write().unwrap() = Some(runtime);
+
+    let stop_requested = Arc::new(AtomicBool::new(false));
+    let worker_stop_requested = stop_requested.clone();
+
+    let join_handle = thread::spawn(move || {
+        if let Some(runtime) = RUNTIME.write().unwrap().as_mut() {
+            runtime.on_start();
+        }
+
+        while !worker_stop_requested.load(Ordering::Acquire) {
+            thread::park();
+        }
+
+        if let Some(runtime) = RUNTIME.write().unwrap().as_mut() {
+            runtime.on_stop();
+        }
+    });
+
+    let thread = join_handle.thread().clone();
+    *worker = Some(Worker {
+        join_handle,
+        thread,
+        stop_requested,
+    });
+}
+
+/// 起動しているランタイムに処理を委譲するためのハンドル。
+///
+/// ランタイムが起動していなければ、中身は`None`になります。
+/// 具体的な型のメソッドを呼ぶには、[`Runtime::as_any_mut`]でダウンキャストしてください。
+///
+/// ```ignore
+/// if let Some(runtime) = runtime_handle().write().unwrap().as_mut() {
+///     if let Some(my_runtime) = runtime.as_any_mut().downcast_mut::<MyRuntime>() {
+///         my_runtime.dispatch(work);
+///     }
+/// }
+/// ```
+pub fn runtime_handle() -> &'static RwLock<Option<Box<dyn Runtime>>> {
+    &RUNTIME
+}
+
+/// ランタイムを停止し、バックグラウンドスレッドの終了を待つ関数。
+///
+/// [`define_unload`]で定義される`unload`から呼ばれ、DLLより長生きするスレッドが
+/// 残らないようにします。ランタイムが起動していなければ何もしません。
+///
+/// [`WORKER`]の書き込みロックを関数全体で保持し続けるため、後始末が完了するまで
+/// [`ensure_started`]による次の起動はブロックされます。
+///
+/// [`define_unload`]: crate::define_unload
+pub fn stop() {
+    let mut worker_guard = WORKER.write().unwrap();
+
+    if let Some(worker) = worker_guard.take() {
+        worker.stop_requested.store(true, Ordering::Release);
+        worker.thread.unpark();
+
+        if let Err(e) = worker.join_handle.join() {
+            eprintln!(
+                "panic in runtime worker thread: {}",
+                panic_payload_to_string(&*e)
+            );
+        }
+    }
+
+    *RUNTIME.write().unwrap() = None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    struct RecordingRuntime {
+        start_count: Arc<AtomicUsize>,
+        stop_count: Arc<AtomicUsize>,
+    }
+
+    impl Runtime for RecordingRuntime {
+        fn on_start(&mut self) {
+            self.start_count.fetch_add(1, AtomicOrdering::SeqCst);
+        }
+
+        fn on_stop(&mut self) {
+            self.stop_count.fetch_add(1, AtomicOrdering::SeqCst);
+        }
+
+        fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+            self
+        }
+    }
+
+    fn wait_until(mut condition: impl FnMut() -> bool) {
+        for _ in 0..200 {
+            if condition() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("condition was not met in time");
+    }
+
+    // register_runtime_factory/ensure_started/stopは同じグローバルな静的変数を共有しているため、
+    // 一連のライフサイクルを一つのテストの中で確認する。
+    mod lifecycle {
+        use super::*;
+
+        #[test]
+        fn ensure_started_is_idempotent_and_stop_joins_the_worker() {
+            let start_count = Arc::new(AtomicUsize::new(0));
+            let stop_count = Arc::new(AtomicUsize::new(0));
+
+            {
+                let start_count = start_count.clone();
+                let stop_count = stop_count.clone();
+                register_runtime_factory(move || {
+                    Box::new(RecordingRuntime {
+                        start_count: start_count.clone(),
+                        stop_count: stop_count.clone(),
+                    }) as Box<dyn Runtime>
+                });
+            }
+
+            // 2回呼んでも、ファクトリが実行されるのは1回だけ。
+            ensure_started();
+            ensure_started();
+
+            wait_until(|| start_count.load(AtomicOrdering::SeqCst) > 0);
+            assert_eq!(start_count.load(AtomicOrdering::SeqCst), 1);
+            assert!(runtime_handle().read().unwrap().is_some());
+
+            stop();
+
+            assert_eq!(stop_count.load(AtomicOrdering::SeqCst), 1);
+            assert!(runtime_handle().read().unwrap().is_none());
+
+            // 停止後も、登録済みのファクトリから再び起動できる。
+            ensure_started();
+            wait_until(|| start_count.load(AtomicOrdering::SeqCst) > 1);
+            assert_eq!(start_count.load(AtomicOrdering::SeqCst), 2);
+
+            stop();
+            assert_eq!(stop_count.load(AtomicOrdering::SeqCst), 2);
+        }
+    }
+}
@@ -0,0 +1,311 @@
+//! SAORI/1.0形式のリクエストのパースと、レスポンスの組み立てを行うモジュール。
+use std::collections::{BTreeMap, HashMap};
+
+use encoding::{
+    label::{encoding_from_whatwg_label, encoding_from_windows_code_page},
+    DecoderTrap, EncoderTrap,
+};
+use winapi::um::winnls::GetOEMCP;
+
+/// パース済みのSAORI/1.0リクエスト。
+///
+/// [`SaoriRequest::parse`]で、生のリクエストバイト列から組み立てます。
+pub struct SaoriRequest {
+    /// `EXECUTE`や`GET Version`など、リクエストの一行目に書かれているコマンド。
+    pub command: String,
+    /// `SAORI/1.0`の`1.0`部分。
+    pub version: String,
+    /// `Charset`ヘッダーの値。省略されていた場合は`None`。
+    pub charset: Option<String>,
+    /// `Argument0`、`Argument1`、……を、宣言された文字コードでデコードして並べたもの。
+    pub arguments: Vec<String>,
+    /// `Charset`と`ArgumentN`を除いた、その他のヘッダー。
+    pub headers: HashMap<String, String>,
+}
+
+impl SaoriRequest {
+    /// 生のリクエストバイト列をパースする関数。
+    ///
+    /// 一行目は`<COMMAND> SAORI/<version>`、それ以降は`Key: Value`形式のヘッダー行が
+    /// 空行まで続くものとして解釈します。`Charset`ヘッダーで宣言された文字コード
+    /// (省略時はOEM codepage)で、ヘッダーの値をデコードします。
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        let mut lines = split_lines(bytes).into_iter();
+
+        let request_line = lines
+            .next()
+            .ok_or_else(|| "empty saori request".to_string())?;
+        let (command, version) = parse_request_line(&request_line)?;
+
+        let mut raw_headers = Vec::new();
+        for line in lines {
+            if line.is_empty() {
+                break;
+            }
+            raw_headers.push(split_header_line(&line)?);
+        }
+
+        let charset_name = raw_headers
+            .iter()
+            .find(|(key, _)| key == "Charset")
+            .map(|(_, value)| String::from_utf8_lossy(value).trim().to_string());
+
+        let encoding = match &charset_name {
+            Some(name) => encoding_from_whatwg_label(&name.to_lowercase())
+                .ok_or_else(|| format!("unsupported charset: {}", name))?,
+            None => {
+                let oem_codepage = unsafe { GetOEMCP() };
+                encoding_from_windows_code_page(oem_codepage as usize)
+                    .ok_or_else(|| format!("unsupport OEM codepage: {}", oem_codepage))?
+            }
+        };
+
+        let mut arguments = BTreeMap::new();
+        let mut headers = HashMap::new();
+
+        for (key, raw_value) in raw_headers {
+            if key == "Charset" {
+                continue;
+            }
+
+            let value = encoding
+                .decode(&raw_value, DecoderTrap::Strict)
+                .map_err(|e| format!("failed to decode header value of {}: {}", key, e))?;
+
+            match key.strip_prefix("Argument").and_then(|i| i.parse::<u32>().ok()) {
+                Some(index) => {
+                    arguments.insert(index, value);
+                }
+                None => {
+                    headers.insert(key, value);
+                }
+            }
+        }
+
+        let arguments = match arguments.keys().next_back() {
+            // 欠番があっても、arguments[n]が必ずArgumentNの値になるよう、空文字列で埋める。
+            Some(&max_index) => (0..=max_index)
+                .map(|i| arguments.remove(&i).unwrap_or_default())
+                .collect(),
+            None => Vec::new(),
+        };
+
+        Ok(Self {
+            command,
+            version,
+            charset: charset_name,
+            arguments,
+            headers,
+        })
+    }
+}
+
+/// SAORI/1.0形式のレスポンスを組み立てるための型。
+///
+/// [`SaoriResponse::build`]で、`request`が返すべき`Vec<i8>`に変換します。
+pub struct SaoriResponse {
+    /// `200`や`400`など、SAORIのステータスコード。
+    pub status: u16,
+    /// `Value0`、`Value1`、……として並べられる結果の値。
+    pub results: Vec<String>,
+    /// `Result`、`ValueN`、`Charset`を除いた、その他のヘッダー。
+    pub headers: HashMap<String, String>,
+    /// レスポンス本文をエンコードする文字コード。
+    pub charset: String,
+}
+
+impl SaoriResponse {
+    /// 指定したステータスコードで、結果もヘッダーも空の`SaoriResponse`を作る関数。
+    ///
+    /// 文字コードは`UTF-8`になります。
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            results: Vec::new(),
+            headers: HashMap::new(),
+            charset: "UTF-8".to_string(),
+        }
+    }
+
+    /// `request`が返すべき`Vec<i8>`を組み立てる関数。
+    ///
+    /// `charset`で宣言した文字コードで本文をエンコードし、末尾に`\0`を付加します。
+    pub fn build(&self) -> Vec<i8> {
+        let mut text = format!(
+            "SAORI/1.0 {} {}\r\n",
+            self.status,
+            status_reason(self.status)
+        );
+
+        text.push_str(&format!("Result: {}\r\n", self.results.len()));
+        for (i, value) in self.results.iter().enumerate() {
+            text.push_str(&format!("Value{}: {}\r\n", i, value));
+        }
+        for (key, value) in &self.headers {
+            text.push_str(&format!("{}: {}\r\n", key, value));
+        }
+        text.push_str(&format!("Charset: {}\r\n", self.charset));
+        text.push_str("\r\n");
+
+        let encoding = encoding_from_whatwg_label(&self.charset.to_lowercase())
+            .unwrap_or(encoding::all::UTF_8);
+
+        let mut bytes = encoding
+            .encode(&text, EncoderTrap::Replace)
+            .unwrap_or_else(|_| text.into_bytes());
+        bytes.push(0);
+
+        bytes.into_iter().map(|b| b as i8).collect()
+    }
+}
+
+/// ステータスコードから、対応する理由句を返す関数。
+fn status_reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        311 => "Not Enough",
+        312 => "Advice",
+        400 => "Bad Request",
+        500 => "Internal Server Error",
+        _ => "Unknown",
+    }
+}
+
+/// 生のバイト列を、`\r\n`区切りの行に分割する関数。
+fn split_lines(bytes: &[u8]) -> Vec<Vec<u8>> {
+    bytes
+        .split(|&b| b == b'\n')
+        .map(|line| match line.last() {
+            Some(b'\r') => line[..line.len() - 1].to_vec(),
+            _ => line.to_vec(),
+        })
+        .collect()
+}
+
+/// 一行目の`<COMMAND> SAORI/<version>`を、コマンドとバージョンに分割する関数。
+///
+/// `GET Version`のように、コマンド自体に空白を含む場合があるため、最後の空白で分割します。
+fn parse_request_line(line: &[u8]) -> Result<(String, String), String> {
+    let text = String::from_utf8(line.to_vec())
+        .map_err(|e| format!("failed to decode request line: {}", e))?;
+    let text = text.trim();
+
+    let (command, version_part) = text
+        .rsplit_once(' ')
+        .ok_or_else(|| format!("malformed saori request line: {}", text))?;
+
+    let version = version_part
+        .strip_prefix("SAORI/")
+        .ok_or_else(|| format!("malformed saori request line: {}", text))?;
+
+    Ok((command.to_string(), version.to_string()))
+}
+
+/// `Key: Value`形式のヘッダー行を、キーと値のバイト列に分割する関数。
+fn split_header_line(line: &[u8]) -> Result<(String, Vec<u8>), String> {
+    let colon = line
+        .iter()
+        .position(|&b| b == b':')
+        .ok_or_else(|| format!("malformed saori header line: {:?}", line))?;
+
+    let key = String::from_utf8(line[..colon].to_vec())
+        .map_err(|e| format!("failed to decode header key: {}", e))?
+        .trim()
+        .to_string();
+
+    let mut value_start = colon + 1;
+    while value_start < line.len() && line[value_start] == b' ' {
+        value_start += 1;
+    }
+
+    Ok((key, line[value_start..].to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod parse_request_line {
+        use super::*;
+
+        #[test]
+        fn checking_value_with_simple_command() {
+            let result = parse_request_line(b"EXECUTE SAORI/1.0").unwrap();
+            assert_eq!(result, ("EXECUTE".to_string(), "1.0".to_string()));
+        }
+
+        #[test]
+        fn checking_value_with_command_containing_space() {
+            let result = parse_request_line(b"GET Version SAORI/1.0").unwrap();
+            assert_eq!(result, ("GET Version".to_string(), "1.0".to_string()));
+        }
+
+        #[test]
+        fn checking_error_without_version() {
+            assert!(parse_request_line(b"EXECUTE").is_err());
+        }
+    }
+
+    mod split_header_line {
+        use super::*;
+
+        #[test]
+        fn checking_value() {
+            let result = split_header_line(b"Argument0: foo").unwrap();
+            assert_eq!(result, ("Argument0".to_string(), b"foo".to_vec()));
+        }
+
+        #[test]
+        fn checking_error_without_colon() {
+            assert!(split_header_line(b"Argument0 foo").is_err());
+        }
+    }
+
+    mod saori_request_parse {
+        use super::*;
+
+        #[test]
+        fn checking_value() {
+            let request = SaoriRequest::parse(
+                b"EXECUTE SAORI/1.0\r\nCharset: UTF-8\r\nArgument0: foo\r\nArgument1: bar\r\nSender: test\r\n\r\n",
+            )
+            .unwrap();
+
+            assert_eq!(request.command, "EXECUTE");
+            assert_eq!(request.version, "1.0");
+            assert_eq!(request.charset, Some("UTF-8".to_string()));
+            assert_eq!(request.arguments, vec!["foo".to_string(), "bar".to_string()]);
+            assert_eq!(request.headers.get("Sender"), Some(&"test".to_string()));
+        }
+
+        #[test]
+        fn checking_value_with_gap_in_argument_index() {
+            let request =
+                SaoriRequest::parse(b"EXECUTE SAORI/1.0\r\nCharset: UTF-8\r\nArgument1: bar\r\n\r\n")
+                    .unwrap();
+
+            assert_eq!(
+                request.arguments,
+                vec!["".to_string(), "bar".to_string()]
+            );
+        }
+    }
+
+    mod saori_response_build {
+        use super::*;
+
+        #[test]
+        fn checking_value() {
+            let mut response = SaoriResponse::new(200);
+            response.results.push("1".to_string());
+
+            let result: Vec<u8> = response.build().into_iter().map(|v| v as u8).collect();
+
+            assert_eq!(
+                result,
+                b"SAORI/1.0 200 OK\r\nResult: 1\r\nValue0: 1\r\nCharset: UTF-8\r\n\r\n\0".to_vec()
+            );
+        }
+    }
+}